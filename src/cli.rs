@@ -1,5 +1,8 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
 use clap::{Parser, Subcommand, ValueEnum};
+use nix::fcntl::PosixFadviseAdvice;
+use nix::libc;
+use nix::libc::off_t;
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 
@@ -12,9 +15,9 @@ pub struct Cli {
     pub command: Command,
 }
 
-/// The scope of a file lock.
+/// The scope of a file lock, as selected via `--scope`.
 #[derive(Clone, Debug, Default, ValueEnum)]
-pub enum LockScope {
+pub enum ScopeKind {
     /// Lock the whole file.
     #[default]
     WholeFile,
@@ -25,12 +28,139 @@ pub enum LockScope {
     WholeByteRange,
 }
 
-impl Display for LockScope {
+impl Display for ScopeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // must match the way clap accepts arguments
+            Self::WholeFile => write!(f, "whole-file"),
+            Self::WholeByteRange => write!(f, "whole-byte-range"),
+        }
+    }
+}
+
+/// How a `--range`'s start offset is interpreted (`l_whence`).
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum RangeWhence {
+    /// Relative to the beginning of the file (`SEEK_SET`).
+    #[default]
+    Set,
+    /// Relative to the current file offset (`SEEK_CUR`).
+    Cur,
+    /// Relative to the end of the file (`SEEK_END`).
+    End,
+}
+
+impl RangeWhence {
+    /// Returns the `libc::SEEK_*` constant this variant corresponds to.
+    pub const fn to_libc_whence(self) -> libc::c_int {
+        match self {
+            Self::Set => libc::SEEK_SET,
+            Self::Cur => libc::SEEK_CUR,
+            Self::End => libc::SEEK_END,
+        }
+    }
+}
+
+impl Display for RangeWhence {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             // must match the way clap accepts arguments
-            LockScope::WholeFile => write!(f, "whole-file"),
-            LockScope::WholeByteRange => write!(f, "whole-byte-range"),
+            Self::Set => write!(f, "set"),
+            Self::Cur => write!(f, "cur"),
+            Self::End => write!(f, "end"),
+        }
+    }
+}
+
+/// Advice values accepted by `posix_fadvise(2)`, mapped to
+/// [`PosixFadviseAdvice`].
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum FadviseAdvice {
+    /// No special advice; the default behavior (`POSIX_FADV_NORMAL`).
+    Normal,
+    /// Expect sequential access (`POSIX_FADV_SEQUENTIAL`).
+    Sequential,
+    /// Expect access in random order (`POSIX_FADV_RANDOM`).
+    Random,
+    /// Expect access in the near future, e.g. to prefetch it
+    /// (`POSIX_FADV_WILLNEED`).
+    Willneed,
+    /// Data will not be accessed again soon, e.g. to drop it from the page
+    /// cache (`POSIX_FADV_DONTNEED`).
+    Dontneed,
+    /// Data will be accessed only once (`POSIX_FADV_NOREUSE`).
+    Noreuse,
+}
+
+impl FadviseAdvice {
+    /// Converts to the corresponding [`PosixFadviseAdvice`].
+    #[must_use]
+    pub const fn to_nix(self) -> PosixFadviseAdvice {
+        match self {
+            Self::Normal => PosixFadviseAdvice::POSIX_FADV_NORMAL,
+            Self::Sequential => PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+            Self::Random => PosixFadviseAdvice::POSIX_FADV_RANDOM,
+            Self::Willneed => PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+            Self::Dontneed => PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            Self::Noreuse => PosixFadviseAdvice::POSIX_FADV_NOREUSE,
+        }
+    }
+}
+
+/// Parses a `--range` value of the form `<start>:<len>` into an
+/// `(l_start, l_len)` pair.
+fn parse_range(s: &str) -> Result<(off_t, off_t), String> {
+    let (start, len) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid range '{s}', expected '<start>:<len>'"))?;
+    let start = start
+        .trim()
+        .parse::<off_t>()
+        .map_err(|e| format!("invalid range start '{start}': {e}"))?;
+    let len = len
+        .trim()
+        .parse::<off_t>()
+        .map_err(|e| format!("invalid range length '{len}': {e}"))?;
+    Ok((start, len))
+}
+
+/// The resolved scope of a file lock, as passed to the `fcntl` module.
+///
+/// This combines the command line's `--scope`, `--range` and `--whence`
+/// arguments into a single value.
+#[derive(Clone, Debug)]
+pub enum LockScope {
+    /// Lock the whole file.
+    WholeFile,
+    /// Lock the whole byte range the file occupies.
+    WholeByteRange,
+    /// Lock an explicit byte range (`l_start`/`l_len`/`l_whence`), e.g. to
+    /// test or exercise sub-range locking, splitting, and coalescing.
+    Range {
+        /// Start offset of the range (`l_start`).
+        start: off_t,
+        /// Length of the range (`l_len`); `0` means "until EOF".
+        len: off_t,
+        /// How `start` is interpreted (`l_whence`).
+        whence: RangeWhence,
+    },
+}
+
+/// Resolves the effective [`LockScope`] from `--scope`, `--range` and
+/// `--whence`. `--range` takes precedence over `--scope` (the two are
+/// mutually exclusive at the CLI level).
+#[must_use]
+pub fn resolve_scope(
+    scope: &ScopeKind,
+    range: Option<(off_t, off_t)>,
+    whence: RangeWhence,
+) -> LockScope {
+    if let Some((start, len)) = range {
+        LockScope::Range { start, len, whence }
+    } else {
+        match scope {
+            ScopeKind::WholeFile => LockScope::WholeFile,
+            ScopeKind::WholeByteRange => LockScope::WholeByteRange,
         }
     }
 }
@@ -51,8 +181,29 @@ pub enum Command {
         #[arg(long = "legacy")]
         dont_use_ofd: bool,
         /// The scope of the file lock.
-        #[arg(long = "scope", default_value_t = LockScope::default())]
-        scope: LockScope,
+        #[arg(long = "scope", default_value_t = ScopeKind::default(), conflicts_with = "range")]
+        scope: ScopeKind,
+        /// Lock (or test) an explicit byte range `<start>:<len>` instead of
+        /// `--scope`, e.g. `--range 4096:8192`.
+        #[arg(long = "range", value_parser = parse_range, conflicts_with = "scope")]
+        range: Option<(off_t, off_t)>,
+        /// How the `--range` start offset is interpreted.
+        #[arg(long = "whence", default_value_t = RangeWhence::default(), requires = "range")]
+        whence: RangeWhence,
+        /// Block until the lock can be acquired (`F_SETLKW`/`F_OFD_SETLKW`)
+        /// instead of failing immediately if the file is already locked.
+        #[arg(long = "wait")]
+        wait: bool,
+        /// Give up waiting for the lock after the given number of seconds.
+        ///
+        /// Requires `--wait`.
+        #[arg(long = "timeout", requires = "wait")]
+        timeout: Option<u64>,
+        /// Run the given command (via the shell) while holding the lock,
+        /// then release the lock and exit with the command's exit code,
+        /// instead of waiting for Enter on stdin.
+        #[arg(long = "command", short = 'c', value_name = "CMD")]
+        command: Option<String>,
     },
     /// Acquire a read (non-exclusive) lock on the given file.
     #[command(name = "read-lock")]
@@ -66,8 +217,29 @@ pub enum Command {
         #[arg(long = "legacy")]
         dont_use_ofd: bool,
         /// The scope of the file lock.
-        #[arg(long = "scope", default_value_t = LockScope::default())]
-        scope: LockScope,
+        #[arg(long = "scope", default_value_t = ScopeKind::default(), conflicts_with = "range")]
+        scope: ScopeKind,
+        /// Lock (or test) an explicit byte range `<start>:<len>` instead of
+        /// `--scope`, e.g. `--range 4096:8192`.
+        #[arg(long = "range", value_parser = parse_range, conflicts_with = "scope")]
+        range: Option<(off_t, off_t)>,
+        /// How the `--range` start offset is interpreted.
+        #[arg(long = "whence", default_value_t = RangeWhence::default(), requires = "range")]
+        whence: RangeWhence,
+        /// Block until the lock can be acquired (`F_SETLKW`/`F_OFD_SETLKW`)
+        /// instead of failing immediately if the file is already locked.
+        #[arg(long = "wait")]
+        wait: bool,
+        /// Give up waiting for the lock after the given number of seconds.
+        ///
+        /// Requires `--wait`.
+        #[arg(long = "timeout", requires = "wait")]
+        timeout: Option<u64>,
+        /// Run the given command (via the shell) while holding the lock,
+        /// then release the lock and exit with the command's exit code,
+        /// instead of waiting for Enter on stdin.
+        #[arg(long = "command", short = 'c', value_name = "CMD")]
+        command: Option<String>,
     },
     /// Test if there is currently a lock on the given file.
     #[command(name = "test-lock")]
@@ -81,7 +253,109 @@ pub enum Command {
         #[arg(long = "legacy")]
         dont_use_ofd: bool,
         /// The scope of the file lock.
-        #[arg(long = "scope", default_value_t = LockScope::default())]
-        scope: LockScope,
+        #[arg(long = "scope", default_value_t = ScopeKind::default(), conflicts_with = "range")]
+        scope: ScopeKind,
+        /// Lock (or test) an explicit byte range `<start>:<len>` instead of
+        /// `--scope`, e.g. `--range 4096:8192`.
+        #[arg(long = "range", value_parser = parse_range, conflicts_with = "scope")]
+        range: Option<(off_t, off_t)>,
+        /// How the `--range` start offset is interpreted.
+        #[arg(long = "whence", default_value_t = RangeWhence::default(), requires = "range")]
+        whence: RangeWhence,
+    },
+    /// Acquire a `flock(2)` write (exclusive) lock on the given file.
+    ///
+    /// Unlike `write-lock`, this uses whole-file BSD locks instead of POSIX
+    /// record locks, which behave more predictably on some platforms (e.g.
+    /// WSL1).
+    #[command(name = "flock-write-lock")]
+    FlockWriteLock {
+        /// Path to file.
+        #[arg()] // positional arg
+        file: PathBuf,
+        /// Block until the lock can be acquired instead of failing
+        /// immediately if the file is already locked.
+        #[arg(long = "wait")]
+        wait: bool,
+        /// Give up waiting for the lock after the given number of seconds.
+        ///
+        /// Requires `--wait`.
+        #[arg(long = "timeout", requires = "wait")]
+        timeout: Option<u64>,
+        /// Run the given command (via the shell) while holding the lock,
+        /// then release the lock and exit with the command's exit code,
+        /// instead of waiting for Enter on stdin.
+        #[arg(long = "command", short = 'c', value_name = "CMD")]
+        command: Option<String>,
+    },
+    /// Acquire a `flock(2)` read (shared) lock on the given file.
+    ///
+    /// Unlike `read-lock`, this uses whole-file BSD locks instead of POSIX
+    /// record locks, which behave more predictably on some platforms (e.g.
+    /// WSL1).
+    #[command(name = "flock-read-lock")]
+    FlockReadLock {
+        /// Path to file.
+        #[arg()] // positional arg
+        file: PathBuf,
+        /// Block until the lock can be acquired instead of failing
+        /// immediately if the file is already locked.
+        #[arg(long = "wait")]
+        wait: bool,
+        /// Give up waiting for the lock after the given number of seconds.
+        ///
+        /// Requires `--wait`.
+        #[arg(long = "timeout", requires = "wait")]
+        timeout: Option<u64>,
+        /// Run the given command (via the shell) while holding the lock,
+        /// then release the lock and exit with the command's exit code,
+        /// instead of waiting for Enter on stdin.
+        #[arg(long = "command", short = 'c', value_name = "CMD")]
+        command: Option<String>,
+    },
+    /// Give the kernel advice about how a file will be accessed, via
+    /// `posix_fadvise(2)`.
+    ///
+    /// For example, `--advice dontneed` drops the given range from the page
+    /// cache, while `--advice willneed` prefetches it.
+    #[command(name = "fadvise")]
+    Fadvise {
+        /// Path to file.
+        #[arg()] // positional arg
+        file: PathBuf,
+        /// The advice to give the kernel.
+        #[arg(long = "advice")]
+        advice: FadviseAdvice,
+        /// The byte range to apply the advice to, given as `<start>:<len>`.
+        ///
+        /// Defaults to the whole file.
+        #[arg(long = "range", value_parser = parse_range)]
+        range: Option<(off_t, off_t)>,
+    },
+    /// Read the close-on-exec descriptor flag (`F_GETFD`) and the open file
+    /// status flags (`F_GETFL`) of the given file.
+    #[command(name = "get-flags")]
+    GetFlags {
+        /// Path to file.
+        #[arg()] // positional arg
+        file: PathBuf,
+    },
+    /// Modify the close-on-exec descriptor flag (`F_SETFD`) and/or the open
+    /// file status flags (`F_SETFL`) of the given file.
+    #[command(name = "set-flags")]
+    SetFlags {
+        /// Path to file.
+        #[arg()] // positional arg
+        file: PathBuf,
+        /// Enable `O_APPEND`: all writes go to the end of the file.
+        #[arg(long = "append")]
+        append: bool,
+        /// Enable `O_NONBLOCK`: I/O on the file does not block.
+        #[arg(long = "nonblock")]
+        nonblock: bool,
+        /// Enable `FD_CLOEXEC`: close the descriptor on a successful
+        /// `execve(2)`.
+        #[arg(long = "cloexec")]
+        cloexec: bool,
     },
 }
@@ -1,14 +1,17 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
 use crate::cli;
-use crate::cli::LockScope;
+use crate::cli::{FadviseAdvice, LockScope};
 use anyhow::anyhow;
 use nix::errno::Errno;
-use nix::fcntl::{fcntl, FcntlArg};
+use nix::fcntl::{fcntl, posix_fadvise, FcntlArg, FdFlag, OFlag};
 use nix::libc;
 use nix::libc::off_t;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::alarm;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
 use std::io;
+use std::os::fd::AsRawFd;
 
 #[derive(Clone, Copy, Debug)]
 pub enum LockType {
@@ -25,25 +28,63 @@ impl LockType {
     }
 }
 
+/// Describes who holds a conflicting lock, as reported by `F_GETLK`/
+/// `F_OFD_GETLK`.
+#[derive(Clone, Copy, Debug)]
+pub struct LockHolder {
+    /// The PID of the process holding the conflicting lock (`l_pid`).
+    ///
+    /// `F_OFD_GETLK` always reports `-1` here, as OFD locks are not owned by
+    /// a single process. In that case, this is `None`.
+    pub pid: Option<libc::pid_t>,
+    /// The start offset of the conflicting lock (`l_start`).
+    pub start: off_t,
+    /// The length of the conflicting lock (`l_len`); `0` means "until EOF".
+    pub len: off_t,
+}
+
+impl Display for LockHolder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let end = if self.len == 0 {
+            "EOF".to_string()
+        } else {
+            (self.start + self.len).to_string()
+        };
+        match self.pid {
+            Some(pid) => write!(f, "held by PID {pid} on bytes [{}, {end})", self.start),
+            // OFD locks aren't owned by a single process; be honest about it
+            // rather than printing a misleading PID.
+            None => write!(f, "held by an OFD lock on bytes [{}, {end})", self.start),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum LockState {
-    ExclusiveWrite,
-    SharedRead,
+    ExclusiveWrite(LockHolder),
+    SharedRead(LockHolder),
     Unlocked,
 }
 
-impl TryFrom<libc::c_int> for LockState {
+impl TryFrom<&libc::flock> for LockState {
     type Error = anyhow::Error;
 
-    fn try_from(value: libc::c_int) -> Result<Self, Self::Error> {
+    fn try_from(value: &libc::flock) -> Result<Self, Self::Error> {
         const F_UNLCK: libc::c_int = libc::F_UNLCK as libc::c_int;
         const F_WRLCK: libc::c_int = libc::F_WRLCK as libc::c_int;
         const F_RDLCK: libc::c_int = libc::F_RDLCK as libc::c_int;
-        match value {
+
+        let l_type = libc::c_int::from(value.l_type);
+        let holder = LockHolder {
+            pid: (value.l_pid != -1).then_some(value.l_pid),
+            start: value.l_start,
+            len: value.l_len,
+        };
+        match l_type {
             F_UNLCK => Ok(Self::Unlocked),
-            F_WRLCK => Ok(Self::ExclusiveWrite),
-            F_RDLCK => Ok(Self::SharedRead),
-            _ => Err(anyhow!("invalid lock type {value}")),
+            F_WRLCK => Ok(Self::ExclusiveWrite(holder)),
+            F_RDLCK => Ok(Self::SharedRead(holder)),
+            _ => Err(anyhow!("invalid lock type {l_type}")),
         }
     }
 }
@@ -51,32 +92,62 @@ impl TryFrom<libc::c_int> for LockState {
 impl Display for LockState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::ExclusiveWrite => f.write_str("Exclusive Write Lock"),
-            Self::SharedRead => f.write_str("Shared Read Lock"),
+            Self::ExclusiveWrite(holder) => write!(f, "Exclusive Write Lock {holder}"),
+            Self::SharedRead(holder) => write!(f, "Shared Read Lock {holder}"),
             Self::Unlocked => f.write_str("Unlocked"),
         }
     }
 }
 
-fn get_flock_len(scope: &LockScope, file: &File) -> anyhow::Result<off_t> {
+/// The `(l_whence, l_start, l_len)` triple that a [`LockScope`] resolves to,
+/// as returned by [`resolve_scope`].
+#[derive(Clone, Copy, Debug)]
+struct ResolvedScope {
+    whence: libc::c_int,
+    start: off_t,
+    len: off_t,
+}
+
+/// Resolves a [`LockScope`] into the `(l_whence, l_start, l_len)` triple
+/// `fcntl()` expects.
+fn resolve_scope(scope: &LockScope, file: &File) -> anyhow::Result<ResolvedScope> {
     match scope {
-        LockScope::WholeFile => Ok(0 /* EOF */),
+        LockScope::WholeFile => Ok(ResolvedScope {
+            whence: libc::SEEK_SET,
+            start: 0,
+            len: 0, // EOF
+        }),
         LockScope::WholeByteRange => {
             let len = file
                 .metadata()
                 .map(|m| m.len())
                 .map_err(|e| anyhow::Error::new(e))?;
-            off_t::try_from(len).map_err(|e| anyhow::Error::new(e))
+            let len = off_t::try_from(len).map_err(|e| anyhow::Error::new(e))?;
+            Ok(ResolvedScope {
+                whence: libc::SEEK_SET,
+                start: 0,
+                len,
+            })
         }
+        LockScope::Range { start, len, whence } => Ok(ResolvedScope {
+            whence: whence.to_libc_whence(),
+            start: *start,
+            len: *len,
+        }),
     }
 }
 
-/// Returns a [`struct@libc::flock`] structure for the whole file.
-const fn get_flock(lock_type: LockType, len: off_t) -> libc::flock {
+/// Returns a [`struct@libc::flock`] structure for the given range.
+const fn get_flock(
+    lock_type: LockType,
+    whence: libc::c_int,
+    start: off_t,
+    len: off_t,
+) -> libc::flock {
     libc::flock {
         l_type: lock_type.to_libc_val() as libc::c_short,
-        l_whence: libc::SEEK_SET as libc::c_short,
-        l_start: 0,
+        l_whence: whence as libc::c_short,
+        l_start: start,
         l_len: len,
         l_pid: 0, /* filled by callee */
     }
@@ -137,24 +208,50 @@ impl TryFrom<&cli::Command> for LockOperation {
     }
 }
 
+/// Whether a lock should be acquired in a blocking or a non-blocking
+/// fashion.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Wait {
+    /// Fail immediately (`F_SETLK`/`F_OFD_SETLK`) if the lock is held.
+    NoWait,
+    /// Block (`F_SETLKW`/`F_OFD_SETLKW`) until the lock becomes available.
+    Wait,
+}
+
+impl From<bool> for Wait {
+    fn from(value: bool) -> Self {
+        if value {
+            Self::Wait
+        } else {
+            Self::NoWait
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
-struct SetLockOperation(LockOperation);
+struct SetLockOperation {
+    operation: LockOperation,
+    wait: Wait,
+}
 
 impl SetLockOperation {
     // allow: To keep 1.74.1 as MSRV
     #[allow(clippy::missing_const_for_fn)]
     fn to_fcntl_arg<'a>(self, flock: &'a libc::flock) -> FcntlArg<'a> {
-        match self.0 {
-            LockOperation::Traditional => FcntlArg::F_SETLK(flock),
+        match (self.operation, self.wait) {
+            (LockOperation::Traditional, Wait::NoWait) => FcntlArg::F_SETLK(flock),
+            (LockOperation::Traditional, Wait::Wait) => FcntlArg::F_SETLKW(flock),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            (LockOperation::OpenFileDescription, Wait::NoWait) => FcntlArg::F_OFD_SETLK(flock),
             #[cfg(any(target_os = "android", target_os = "linux"))]
-            LockOperation::OpenFileDescription => FcntlArg::F_OFD_SETLK(flock),
+            (LockOperation::OpenFileDescription, Wait::Wait) => FcntlArg::F_OFD_SETLKW(flock),
         }
     }
 }
 
-impl From<LockOperation> for SetLockOperation {
-    fn from(value: LockOperation) -> Self {
-        Self(value)
+impl SetLockOperation {
+    const fn new(operation: LockOperation, wait: Wait) -> Self {
+        Self { operation, wait }
     }
 }
 
@@ -188,39 +285,98 @@ impl Display for FileAlreadyLockedError {
 
 impl std::error::Error for FileAlreadyLockedError {}
 
-/// Tries to acquire a lock using [`fcntl`] with respect to the given
-/// parameters.
-///
-/// Please note that `fcntl()` locks are **advisory locks**, which do not
-/// prevent to `open()` a file if a lock is already placed.
-///
-/// # Parameters
-/// - `file`: The file to acquire a lock for [`LockType`]
-/// - `lock_type`: The [`LockType`]
-/// - `operation`: The [`LockOperation`]
-/// - `scope`: The [`LockScope`]
-pub fn try_acquire_lock(
-    file: &mut File,
+/// Error returned when waiting for a lock exceeded the configured
+/// `--timeout`.
+#[derive(Copy, Clone, Debug)]
+pub struct LockTimeoutError;
+
+impl Display for LockTimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Timed out while waiting for the lock")
+    }
+}
+
+impl std::error::Error for LockTimeoutError {}
+
+/// No-op signal handler. Its only purpose is to make sure `SIGALRM` does not
+/// terminate the process (the default disposition) but instead merely
+/// interrupts the blocking `fcntl()`/`flock()` call with `EINTR`.
+extern "C" fn sigalrm_handler(_: libc::c_int) {}
+
+/// Arms a one-shot `SIGALRM` (via `alarm(2)`) that fires after `timeout`
+/// seconds, so that a blocking lock call returns `EINTR` instead of hanging
+/// forever. No-op if `timeout` is `None`.
+fn arm_timeout(timeout: Option<u64>) -> anyhow::Result<()> {
+    let Some(secs) = timeout else {
+        return Ok(());
+    };
+    // Installed without `SA_RESTART`, as the kernel would otherwise restart
+    // the interrupted lock call instead of returning `EINTR`.
+    let action = SigAction::new(
+        SigHandler::Handler(sigalrm_handler),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only returns and touches no shared state.
+    unsafe {
+        signal::sigaction(Signal::SIGALRM, &action)?;
+    }
+    let secs = u32::try_from(secs).unwrap_or(u32::MAX);
+    alarm::set(secs);
+    Ok(())
+}
+
+/// Disarms a pending `SIGALRM` previously armed via [`arm_timeout`].
+fn disarm_timeout(timeout: Option<u64>) {
+    if timeout.is_some() {
+        alarm::cancel();
+    }
+}
+
+/// Shared retry loop behind [`LockGuard::acquire`]: issues the `fcntl()`
+/// lock request for the given already-resolved range, retrying on `EINTR`
+/// while `wait` is set and no `timeout` was given. With a `timeout`, an
+/// `EINTR` is reported as [`LockTimeoutError`].
+fn do_lock(
+    file: &File,
     lock_type: LockType,
     operation: LockOperation,
-    scope: &LockScope,
+    wait: bool,
+    timeout: Option<u64>,
+    range: ResolvedScope,
 ) -> anyhow::Result<()> {
-    // Ensure that clippy understands we want a mutable binding.
-    // We mark the binding as mutable as meta state for that file will be
-    // altered in the callee (the kernel).
-    let file: &mut File = file;
-    let operation = SetLockOperation::from(operation);
-    let flock_len = get_flock_len(scope, file)?;
-    let flock = get_flock(lock_type, flock_len);
-    let arg = operation.to_fcntl_arg(&flock);
+    let operation = SetLockOperation::new(operation, Wait::from(wait));
+    let flock = get_flock(lock_type, range.whence, range.start, range.len);
 
-    let res = fcntl(file, arg);
-    match res {
-        Ok(_) => Ok(()),
-        // See man page for error code:
-        // <https://man7.org/linux/man-pages/man2/fcntl.2.html>
-        Err(Errno::EAGAIN | Errno::EACCES) => Err(FileAlreadyLockedError.into()),
-        Err(e) => Err(anyhow!("error trying to get {lock_type:?} lock {e:?}")),
+    if wait {
+        arm_timeout(timeout)?;
+    }
+    loop {
+        let arg = operation.to_fcntl_arg(&flock);
+        let res = fcntl(file, arg);
+        match res {
+            Ok(_) => {
+                disarm_timeout(timeout);
+                return Ok(());
+            }
+            // See man page for error code:
+            // <https://man7.org/linux/man-pages/man2/fcntl.2.html>
+            Err(Errno::EAGAIN | Errno::EACCES) => {
+                disarm_timeout(timeout);
+                return Err(FileAlreadyLockedError.into());
+            }
+            // A blocking F_SETLKW/F_OFD_SETLKW call was interrupted. If a
+            // `--timeout` was armed, treat this as a timeout; otherwise this
+            // was some unrelated signal and we keep waiting.
+            Err(Errno::EINTR) if wait && timeout.is_some() => {
+                return Err(LockTimeoutError.into());
+            }
+            Err(Errno::EINTR) if wait => continue,
+            Err(e) => {
+                disarm_timeout(timeout);
+                return Err(anyhow!("error trying to get {lock_type:?} lock {e:?}"));
+            }
+        }
     }
 }
 
@@ -237,15 +393,241 @@ pub fn get_lock_state(
     scope: &LockScope,
 ) -> anyhow::Result<LockState> {
     let operation = GetLockOperation::from(operation);
-    let flock_len = get_flock_len(scope, file)?;
-    let mut flock = get_flock(LockType::Write, flock_len);
+    let range = resolve_scope(scope, file)?;
+    let mut flock = get_flock(LockType::Write, range.whence, range.start, range.len);
     let arg = operation.to_fcntl_arg(&mut flock);
     let ret = fcntl(file, arg)?;
     if ret != 0 {
         Err(io::Error::last_os_error().into())
     } else {
-        let state = flock.l_type as libc::c_int;
-        let state = LockState::try_from(state)?;
-        Ok(state)
+        LockState::try_from(&flock)
+    }
+}
+
+/// RAII guard around a `fcntl()` lock acquired via [`LockGuard::acquire`].
+///
+/// Dropping the guard (also while unwinding from a panic) releases the lock:
+/// for [`LockOperation::Traditional`] it issues an explicit
+/// `F_SETLK(F_UNLCK)`; for OFD locks it is enough to close the file
+/// descriptor, which happens implicitly when the held [`File`] is dropped. If
+/// the process is killed, the guard is not dropped; the kernel then releases
+/// the lock when it closes the process's descriptors.
+#[derive(Debug)]
+pub struct LockGuard {
+    file: File,
+    operation: LockOperation,
+    range: ResolvedScope,
+}
+
+impl LockGuard {
+    /// Acquires a lock using [`do_lock`], returning a guard that releases
+    /// the lock once it is dropped.
+    pub fn acquire(
+        file: File,
+        lock_type: LockType,
+        operation: LockOperation,
+        scope: &LockScope,
+        wait: bool,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let range = resolve_scope(scope, &file)?;
+        do_lock(&file, lock_type, operation, wait, timeout, range)?;
+        Ok(Self {
+            file,
+            operation,
+            range,
+        })
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if matches!(self.operation, LockOperation::Traditional) {
+            let flock = libc::flock {
+                l_type: libc::F_UNLCK as libc::c_short,
+                l_whence: self.range.whence as libc::c_short,
+                l_start: self.range.start,
+                l_len: self.range.len,
+                l_pid: 0,
+            };
+            // Best-effort: nothing useful can be done with an error while
+            // dropping, and the kernel releases all of a process's locks on
+            // this file anyway once the last of its descriptors is closed.
+            let _ = fcntl(&self.file, FcntlArg::F_SETLK(&flock));
+        }
+        // For OFD locks, closing `self.file` right after this (as part of
+        // this same drop) releases the lock; no explicit unlock is needed.
+    }
+}
+
+// --------------------------------------------------------------------------
+// flock(2) (BSD-style) locks
+//
+// These are a separate locking mechanism from the POSIX `fcntl()` record
+// locks above: they are whole-file only (no `l_start`/`l_len`/`l_whence`),
+// they are associated with the *open file description* rather than the
+// process, and they behave differently across `fork()`/`dup()` (the lock is
+// shared by all descriptors that refer to the same open file description,
+// not re-acquired per process like traditional `fcntl()` locks). They exist
+// here as a portable fallback for environments where POSIX record locks are
+// unreliable, such as WSL1.
+// --------------------------------------------------------------------------
+
+/// Tries to acquire a whole-file lock using `flock(2)` with respect to the
+/// given parameters.
+///
+/// Please note that `flock()` locks, just like `fcntl()` locks, are
+/// **advisory locks**, which do not prevent to `open()` a file if a lock is
+/// already placed.
+///
+/// # Parameters
+/// - `file`: The file to acquire a lock for [`LockType`]
+/// - `lock_type`: The [`LockType`]
+/// - `wait`: Whether to block until the lock becomes available instead of
+///   failing immediately.
+/// - `timeout`: Give up waiting after this many seconds. Only has an effect
+///   if `wait` is `true`.
+pub fn try_acquire_flock(
+    file: &File,
+    lock_type: LockType,
+    wait: bool,
+    timeout: Option<u64>,
+) -> anyhow::Result<()> {
+    let operation = match (lock_type, wait) {
+        (LockType::Write, false) => libc::LOCK_EX | libc::LOCK_NB,
+        (LockType::Write, true) => libc::LOCK_EX,
+        (LockType::Read, false) => libc::LOCK_SH | libc::LOCK_NB,
+        (LockType::Read, true) => libc::LOCK_SH,
+    };
+    let fd = file.as_raw_fd();
+
+    if wait {
+        arm_timeout(timeout)?;
+    }
+    loop {
+        // SAFETY: `fd` is a valid, open file descriptor for the lifetime of
+        // this call.
+        let res = unsafe { libc::flock(fd, operation) };
+        if res == 0 {
+            disarm_timeout(timeout);
+            return Ok(());
+        }
+        match Errno::last() {
+            Errno::EAGAIN => {
+                disarm_timeout(timeout);
+                return Err(FileAlreadyLockedError.into());
+            }
+            Errno::EINTR if wait && timeout.is_some() => {
+                return Err(LockTimeoutError.into());
+            }
+            Errno::EINTR if wait => continue,
+            e => {
+                disarm_timeout(timeout);
+                return Err(anyhow!("error trying to get {lock_type:?} flock {e:?}"));
+            }
+        }
+    }
+}
+
+/// RAII guard around a `flock(2)` lock acquired via [`FlockGuard::acquire`].
+///
+/// Dropping the guard (also while unwinding from a panic) releases the lock:
+/// it issues an explicit `flock(LOCK_UN)`, rather than relying on the
+/// implicit release that happens once the last descriptor referring to this
+/// open file description is closed. If the process is killed, the guard is
+/// not dropped and only that implicit release applies.
+#[derive(Debug)]
+pub struct FlockGuard {
+    file: File,
+}
+
+impl FlockGuard {
+    /// Acquires a lock exactly like [`try_acquire_flock`], but returns a
+    /// guard that releases the lock once it is dropped.
+    pub fn acquire(
+        file: File,
+        lock_type: LockType,
+        wait: bool,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        try_acquire_flock(&file, lock_type, wait, timeout)?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for FlockGuard {
+    fn drop(&mut self) {
+        // Best-effort: nothing useful can be done with an error while
+        // dropping, and the kernel releases the lock anyway once the last
+        // descriptor referring to this open file description is closed.
+        let _ = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Gives the kernel advice about the expected access pattern for
+/// `[offset, offset + len)` of `file` (or `[offset, EOF)` if `len` is `0`)
+/// using `posix_fadvise(2)`.
+pub fn fadvise(
+    file: &File,
+    offset: off_t,
+    len: off_t,
+    advice: FadviseAdvice,
+) -> anyhow::Result<()> {
+    posix_fadvise(file, offset, len, advice.to_nix())
+        .map_err(|e| anyhow!("error calling posix_fadvise: {e:?}"))?;
+    Ok(())
+}
+
+/// The descriptor flags (`F_GETFD`) and open file status flags (`F_GETFL`)
+/// of a file.
+#[derive(Clone, Copy, Debug)]
+pub struct FileFlags {
+    /// Whether `FD_CLOEXEC` (close-on-exec) is set.
+    close_on_exec: bool,
+    /// The open file status flags, e.g. `O_APPEND`/`O_NONBLOCK`/`O_ASYNC`.
+    status: OFlag,
+}
+
+impl Display for FileFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "close-on-exec (FD_CLOEXEC): {}", self.close_on_exec)?;
+        write!(f, "status flags: {:?}", self.status)
+    }
+}
+
+/// Reads the descriptor flags (`F_GETFD`) and open file status flags
+/// (`F_GETFL`) of `file`.
+pub fn get_flags(file: &File) -> anyhow::Result<FileFlags> {
+    let fd_flags = fcntl(file, FcntlArg::F_GETFD)?;
+    let close_on_exec = FdFlag::from_bits_truncate(fd_flags).contains(FdFlag::FD_CLOEXEC);
+    let fl_flags = fcntl(file, FcntlArg::F_GETFL)?;
+    let status = OFlag::from_bits_truncate(fl_flags);
+    Ok(FileFlags {
+        close_on_exec,
+        status,
+    })
+}
+
+/// Sets the close-on-exec descriptor flag (`F_SETFD`) and/or the open file
+/// status flags (`F_SETFL`) of `file`, enabling `cloexec`/`append`/
+/// `nonblock` on top of the flags already set.
+pub fn set_flags(file: &File, append: bool, nonblock: bool, cloexec: bool) -> anyhow::Result<()> {
+    if cloexec {
+        let current = fcntl(file, FcntlArg::F_GETFD)?;
+        let mut fd_flags = FdFlag::from_bits_truncate(current);
+        fd_flags.insert(FdFlag::FD_CLOEXEC);
+        fcntl(file, FcntlArg::F_SETFD(fd_flags))?;
+    }
+    if append || nonblock {
+        let current = fcntl(file, FcntlArg::F_GETFL)?;
+        let mut status = OFlag::from_bits_truncate(current);
+        if append {
+            status.insert(OFlag::O_APPEND);
+        }
+        if nonblock {
+            status.insert(OFlag::O_NONBLOCK);
+        }
+        fcntl(file, FcntlArg::F_SETFL(status))?;
     }
+    Ok(())
 }
@@ -28,8 +28,22 @@
 //! | `F_SETLK`     | ✅         |
 //! | `F_OFD_GETLK` | ✅         |
 //! | `F_OFD_SETLK` | ✅         |
+//! | `F_SETLKW`    | ✅         |
+//! | `F_OFD_SETLKW`| ✅         |
+//! | `F_GETFD`     | ✅         |
+//! | `F_SETFD`     | ✅         |
+//! | `F_GETFL`     | ✅         |
+//! | `F_SETFL`     | ✅         |
 //! | ...           | Not yet   |
 //!
+//! ## `flock(2)` as an Alternative
+//!
+//! POSIX record locks (`fcntl`) are known to behave unreliably in some
+//! environments, most notably WSL1. As a portable fallback, this tool also
+//! supports whole-file `flock(2)` locks via the `flock-write-lock` and
+//! `flock-read-lock` subcommands. Unlike `fcntl` locks, `flock` locks don't
+//! support byte ranges and are tied to the open file description rather than
+//! the process, so they are inherited differently across `fork`/`dup`.
 //!
 //! ## CLI Usage
 //!
@@ -72,27 +86,141 @@ fn open_file(path: &Path, write: bool) -> anyhow::Result<File> {
         .map_err(|e| e.into())
 }
 
+/// Runs `cmd` via the shell, waits for it to exit, and returns its exit
+/// code.
+fn run_command(cmd: &str) -> anyhow::Result<i32> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .status()?;
+    Ok(status.code().unwrap_or(1))
+}
+
 fn main() -> anyhow::Result<()> {
     let cli: cli::Cli = cli::Cli::parse();
 
     match &cli.command {
-        cmd @ cli::Command::WriteLock { file: path, scope, .. } => {
-            let mut file = open_file(path, true)?;
+        cmd @ cli::Command::WriteLock {
+            file: path,
+            scope,
+            range,
+            whence,
+            wait,
+            timeout,
+            command,
+            ..
+        } => {
+            let file = open_file(path, true)?;
             let operation = fcntl::LockOperation::try_from(cmd)?;
-            fcntl::try_acquire_lock(&mut file, fcntl::LockType::Write, operation, scope)?;
+            let scope = cli::resolve_scope(scope, *range, *whence);
+            let guard = fcntl::LockGuard::acquire(
+                file,
+                fcntl::LockType::Write,
+                operation,
+                &scope,
+                *wait,
+                *timeout,
+            )?;
+            if let Some(command) = command {
+                let code = run_command(command)?;
+                drop(guard);
+                std::process::exit(code);
+            }
             wait_for_enter(fcntl::LockType::Write, path);
         }
-        cmd @ cli::Command::ReadLock { file: path, scope, .. } => {
-            let mut file = open_file(path, false)?;
+        cmd @ cli::Command::ReadLock {
+            file: path,
+            scope,
+            range,
+            whence,
+            wait,
+            timeout,
+            command,
+            ..
+        } => {
+            let file = open_file(path, false)?;
             let operation = fcntl::LockOperation::try_from(cmd)?;
-            fcntl::try_acquire_lock(&mut file, fcntl::LockType::Read, operation, scope)?;
+            let scope = cli::resolve_scope(scope, *range, *whence);
+            let guard = fcntl::LockGuard::acquire(
+                file,
+                fcntl::LockType::Read,
+                operation,
+                &scope,
+                *wait,
+                *timeout,
+            )?;
+            if let Some(command) = command {
+                let code = run_command(command)?;
+                drop(guard);
+                std::process::exit(code);
+            }
             wait_for_enter(fcntl::LockType::Read, path);
         }
-        cmd @ cli::Command::TestLock { file: path, scope, .. } => {
+        cmd @ cli::Command::TestLock {
+            file: path,
+            scope,
+            range,
+            whence,
+            ..
+        } => {
             let file = open_file(path, false)?;
             let operation = fcntl::LockOperation::try_from(cmd)?;
-            let state = fcntl::get_lock_state(&file, operation, scope)?;
-            println!("state: {state:?}");
+            let scope = cli::resolve_scope(scope, *range, *whence);
+            let state = fcntl::get_lock_state(&file, operation, &scope)?;
+            println!("state: {state}");
+        }
+        cli::Command::FlockWriteLock {
+            file: path,
+            wait,
+            timeout,
+            command,
+        } => {
+            let file = open_file(path, true)?;
+            let guard = fcntl::FlockGuard::acquire(file, fcntl::LockType::Write, *wait, *timeout)?;
+            if let Some(command) = command {
+                let code = run_command(command)?;
+                drop(guard);
+                std::process::exit(code);
+            }
+            wait_for_enter(fcntl::LockType::Write, path);
+        }
+        cli::Command::FlockReadLock {
+            file: path,
+            wait,
+            timeout,
+            command,
+        } => {
+            let file = open_file(path, false)?;
+            let guard = fcntl::FlockGuard::acquire(file, fcntl::LockType::Read, *wait, *timeout)?;
+            if let Some(command) = command {
+                let code = run_command(command)?;
+                drop(guard);
+                std::process::exit(code);
+            }
+            wait_for_enter(fcntl::LockType::Read, path);
+        }
+        cli::Command::Fadvise {
+            file: path,
+            advice,
+            range,
+        } => {
+            let file = open_file(path, false)?;
+            let (offset, len) = range.unwrap_or((0, 0));
+            fcntl::fadvise(&file, offset, len, *advice)?;
+        }
+        cli::Command::GetFlags { file: path } => {
+            let file = open_file(path, false)?;
+            let flags = fcntl::get_flags(&file)?;
+            println!("{flags}");
+        }
+        cli::Command::SetFlags {
+            file: path,
+            append,
+            nonblock,
+            cloexec,
+        } => {
+            let file = open_file(path, true)?;
+            fcntl::set_flags(&file, *append, *nonblock, *cloexec)?;
         }
     }
     Ok(())